@@ -0,0 +1,109 @@
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::Sdl;
+
+const SAMPLE_RATE: f32 = 44100.0;
+const BUFFER_SIZE: usize = 2048;
+
+// One-pole low-pass to round off the square wave's edges, followed by a
+// DC-blocking high-pass, so toggling the buzzer on/off doesn't click.
+const LPF_ALPHA: f32 = 0.2;
+const HPF_POLE: f32 = 0.995;
+
+struct Buzzer {
+    phase: f32,
+    phase_inc: f32,
+    volume: f32,
+    enabled: bool,
+    lpf_prev: f32,
+    hpf_prev_in: f32,
+    hpf_prev_out: f32,
+}
+
+impl AudioCallback for Buzzer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            let raw = if !self.enabled {
+                0.0
+            } else if self.phase < 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+
+            self.lpf_prev += LPF_ALPHA * (raw - self.lpf_prev);
+            let filtered = self.lpf_prev;
+
+            let hpf_out = filtered - self.hpf_prev_in + HPF_POLE * self.hpf_prev_out;
+            self.hpf_prev_in = filtered;
+            self.hpf_prev_out = hpf_out;
+
+            *sample = hpf_out;
+        }
+    }
+}
+
+pub struct Audio {
+    device: AudioDevice<Buzzer>,
+    primed: bool,
+    pub frequency: f32,
+    pub volume: f32,
+}
+
+impl Audio {
+    pub fn new(sdl: &Sdl) -> Audio {
+        let audio_subsystem = sdl.audio().unwrap();
+
+        let desired_spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(BUFFER_SIZE as u16),
+        };
+
+        let device = audio_subsystem.open_playback(None, &desired_spec, |_spec| {
+            Buzzer {
+                phase: 0.0,
+                phase_inc: 440.0 / SAMPLE_RATE,
+                volume: 0.25,
+                enabled: false,
+                lpf_prev: 0.0,
+                hpf_prev_in: 0.0,
+                hpf_prev_out: 0.0,
+            }
+        }).unwrap();
+
+        Audio {
+            device,
+            primed: false,
+            frequency: 440.0,
+            volume: 0.25,
+        }
+    }
+
+    // Call every cycle with the current sound timer value; starts or stops
+    // the buzzer to match `st > 0` without ever hearing a raw, unfiltered edge.
+    pub fn update(&mut self, st: u8) {
+        let active = st > 0;
+
+        {
+            let mut buzzer = self.device.lock();
+            buzzer.enabled = active;
+            buzzer.phase_inc = self.frequency / SAMPLE_RATE;
+            buzzer.volume = self.volume;
+        }
+
+        if active && !self.primed {
+            let mut warmup = [0f32; BUFFER_SIZE];
+            self.device.lock().callback(&mut warmup);
+            self.primed = true;
+        }
+
+        if active {
+            self.device.resume();
+        } else {
+            self.device.pause();
+        }
+    }
+}
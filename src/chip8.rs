@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 
-use std::path::Path;
-use std::fs::File;
-use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::time::SystemTime;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 
 use rand::{thread_rng, Rng};
@@ -11,6 +12,8 @@ use sdl2::rect::Rect;
 use ep::FromPrimitive;
 
 use screen::Screen;
+use audio::Audio;
+use jit::{self, Jit};
 use instruction::{Opcodes, Instruction};
 
 const FONT_SET: [u8; 80] = [
@@ -32,6 +35,57 @@ const FONT_SET: [u8; 80] = [
     0xf0, 0x80, 0xf0, 0x80, 0x80, // F
 ];
 
+pub const DBG_CPU:   u8 = 0b001;
+pub const DBG_RDMEM: u8 = 0b010;
+pub const DBG_WRMEM: u8 = 0b100;
+
+// Several opcodes were implemented one specific way in the original COSMAC
+// VIP interpreter and a different way in later CHIP-48/SCHIP interpreters;
+// ROMs are written against whichever their author targeted.
+#[derive(Clone, Copy)]
+pub struct Quirks {
+    pub shift_uses_vy:            bool,
+    pub load_store_increments_i:  bool,
+    pub inclusive_register_range: bool,
+    pub vf_reset_on_logic:        bool,
+    // BNNN on the COSMAC VIP and CHIP-48 jumps to `nnn + V0`; SCHIP 1.1
+    // changed it to `nnn + Vx`, where `x` is the top nibble of `nnn` --
+    // `jmp_va` branches on this to pick the offset register.
+    pub jump_offset_uses_vx:      bool,
+}
+
+impl Quirks {
+    pub fn chip8() -> Quirks {
+        Quirks {
+            shift_uses_vy:            true,
+            load_store_increments_i:  true,
+            inclusive_register_range: true,
+            vf_reset_on_logic:        true,
+            jump_offset_uses_vx:      false,
+        }
+    }
+
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy:            false,
+            load_store_increments_i:  false,
+            inclusive_register_range: true,
+            vf_reset_on_logic:        false,
+            jump_offset_uses_vx:      false,
+        }
+    }
+
+    pub fn schip() -> Quirks {
+        Quirks {
+            shift_uses_vy:            false,
+            load_store_increments_i:  false,
+            inclusive_register_range: true,
+            vf_reset_on_logic:        false,
+            jump_offset_uses_vx:      true,
+        }
+    }
+}
+
 pub struct Chip8 {
     regs:   [u8; 16],
     i:      u16, 
@@ -43,9 +97,16 @@ pub struct Chip8 {
     jmp:    bool,
 
     keys:   [u8; 16],
-    mem:    [u8; 4096],
+    mem:    [u8; 0x10000],
     stack:  Vec<usize>,
     screen: Screen,
+    audio:  Audio,
+
+    debug:       u8,
+    breakpoints: Vec<usize>,
+
+    jit:    Option<Jit>,
+    quirks: Quirks,
 }
 
 impl Debug for Chip8 {
@@ -55,7 +116,7 @@ impl Debug for Chip8 {
 }
 
 impl Chip8 {
-    pub fn new(sdl: &Sdl) -> Chip8 {
+    pub fn new(sdl: &Sdl, quirks: Quirks) -> Chip8 {
         Chip8 {
             regs:   [0; 16],
             i:      0,
@@ -65,9 +126,112 @@ impl Chip8 {
             inst:   Instruction::new(),
             jmp:    false,
             keys:   [0; 16],
-            mem:    [0; 4096], 
+            mem:    [0; 0x10000], 
             stack:  vec![],
             screen: Screen::new(sdl),
+            audio:  Audio::new(sdl),
+            debug:       0,
+            breakpoints: vec![],
+
+            jit: None,
+            quirks,
+        }
+    }
+
+    pub fn set_debug(&mut self, flags: u8) {
+        self.debug = flags;
+    }
+
+    // Enables the JIT backend for hot code paths. Off by default: the
+    // interpreter alone is simpler to trust, so this is opt-in.
+    pub fn enable_jit(&mut self) {
+        self.jit = Some(Jit::new());
+    }
+
+    // Compiles (if needed) and runs the native block at the current PC,
+    // then updates `pc`/timers exactly as one `run()` interpreter step
+    // would. Because a whole block executes per call, `dt`/`st` only tick
+    // once per block rather than once per CHIP-8 instruction inside it --
+    // an acceptable trade for hot loops, which is what the JIT targets.
+    fn run_jit_block(&mut self) {
+        let pc = self.pc;
+
+        let raw_data = (self.mem[pc], self.mem[pc.wrapping_add(1)]);
+        self.inst.decode(raw_data);
+
+        // Only straight-line ALU/load ops (and unconditional jumps) can
+        // start a block; everything else needs interpreter state, so just
+        // run it the normal way instead of compiling a trivial one-op block.
+        let translatable = self.inst.opcode != 0xF000
+            && Opcodes::from_u16(self.inst.opcode)
+                .map_or(false, jit::starts_block);
+
+        if !translatable {
+            self.run_interpreted();
+            return;
+        }
+
+        if self.jit.as_ref().unwrap().lookup(pc).is_none() {
+            self.jit.as_mut().unwrap().compile(
+                &self.mem, pc, self.quirks.vf_reset_on_logic, self.quirks.shift_uses_vy
+            );
+        }
+
+        let regs_ptr = self.regs.as_mut_ptr();
+        let i_ptr = &mut self.i as *mut u16;
+
+        let resume_pc = {
+            let jit = self.jit.as_ref().unwrap();
+            let block = jit.lookup(pc).unwrap();
+            jit.call(block, regs_ptr, i_ptr)
+        };
+
+        self.pc = resume_pc as usize;
+
+        if self.dt > 0 { self.dt -= 1; }
+        if self.st > 0 { self.st -= 1; }
+
+        self.audio.update(self.st);
+
+        if let Some(ref mut jit) = self.jit {
+            jit.link(pc, self.pc);
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.push(pc);
+    }
+
+    fn read_mem(&self, addr: usize) -> u8 {
+        let value = self.mem[addr];
+        if self.debug & DBG_RDMEM != 0 {
+            println!("  rd mem[{:#06x}] = {:#04x}", addr, value);
+        }
+        value
+    }
+
+    fn write_mem(&mut self, addr: usize, value: u8) {
+        if self.debug & DBG_WRMEM != 0 {
+            println!("  wr mem[{:#06x}] = {:#04x}", addr, value);
+        }
+        self.mem[addr] = value;
+    }
+
+    // Executes a single opcode through the interpreter, even when the JIT is
+    // enabled, for callers driving the machine one instruction at a time
+    // under the debugger.
+    pub fn step(&mut self) {
+        self.run_interpreted();
+    }
+
+    // Steps until the PC lands on a registered breakpoint, without executing
+    // the instruction at that address.
+    pub fn continue_until_break(&mut self) {
+        loop {
+            if self.breakpoints.contains(&self.pc) {
+                break;
+            }
+            self.step();
         }
     }
 
@@ -84,56 +248,191 @@ impl Chip8 {
         }
     }
 
+    pub fn save_state(&self, path: &str) {
+        let mut file = File::create(Path::new(path)).unwrap();
+
+        file.write_all(&self.regs).unwrap();
+        file.write_all(&self.i.to_le_bytes()).unwrap();
+        file.write_all(&[self.dt, self.st]).unwrap();
+        file.write_all(&(self.pc as u16).to_le_bytes()).unwrap();
+        file.write_all(&self.keys).unwrap();
+        file.write_all(&self.mem).unwrap();
+
+        file.write_all(&(self.stack.len() as u16).to_le_bytes()).unwrap();
+        for addr in &self.stack {
+            file.write_all(&(*addr as u16).to_le_bytes()).unwrap();
+        }
+
+        file.write_all(&self.screen.buffer).unwrap();
+    }
+
+    // Loads a snapshot written by `save_state`. A truncated or foreign file
+    // would otherwise panic partway through a `copy_from_slice`; the length
+    // is checked up front (and again once the variable-length stack section
+    // is known) so a bad file is just ignored instead.
+    pub fn load_state(&mut self, path: &str) {
+        let mut file = File::open(Path::new(path)).unwrap();
+        let mut blob = Vec::new();
+        file.read_to_end(&mut blob).unwrap();
+
+        let regs_len = self.regs.len();
+        let keys_len = self.keys.len();
+        let mem_len = self.mem.len();
+        let buffer_len = self.screen.buffer.len();
+
+        let fixed_len = regs_len + 2 + 2 + 2 + keys_len + mem_len + 2;
+        if blob.len() < fixed_len {
+            return;
+        }
+
+        let mut pos = 0usize;
+
+        self.regs.copy_from_slice(&blob[pos..pos + regs_len]);
+        pos += regs_len;
+
+        self.i = u16::from_le_bytes([blob[pos], blob[pos + 1]]);
+        pos += 2;
+
+        self.dt = blob[pos];
+        self.st = blob[pos + 1];
+        pos += 2;
+
+        self.pc = u16::from_le_bytes([blob[pos], blob[pos + 1]]) as usize;
+        pos += 2;
+
+        self.keys.copy_from_slice(&blob[pos..pos + keys_len]);
+        pos += keys_len;
+
+        self.mem.copy_from_slice(&blob[pos..pos + mem_len]);
+        pos += mem_len;
+
+        let stack_len = u16::from_le_bytes([blob[pos], blob[pos + 1]]) as usize;
+        pos += 2;
+
+        if blob.len() < pos + stack_len * 2 + buffer_len {
+            return;
+        }
+
+        self.stack.clear();
+        for _ in 0..stack_len {
+            let addr = u16::from_le_bytes([blob[pos], blob[pos + 1]]) as usize;
+            self.stack.push(addr);
+            pos += 2;
+        }
+
+        self.screen.buffer.copy_from_slice(&blob[pos..pos + buffer_len]);
+    }
+
+    // Scans `dir` for snapshots whose filename starts with `rom_name` and
+    // loads whichever was modified most recently, since save_state doesn't
+    // encode an ordering into the filename itself. Pass the directory the
+    // `save_state` paths were written into, since nothing outside it is
+    // visible here.
+    pub fn load_latest_state(&mut self, dir: &str, rom_name: &str) {
+        let saves_dir = Path::new(dir);
+        let mut latest: Option<(SystemTime, PathBuf)> = None;
+
+        for entry in fs::read_dir(saves_dir).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+
+            if !name.to_string_lossy().starts_with(rom_name) {
+                continue;
+            }
+
+            let modified = entry.metadata().unwrap().modified().unwrap();
+            if latest.as_ref().map_or(true, |(t, _)| modified > *t) {
+                latest = Some((modified, entry.path()));
+            }
+        }
+
+        if let Some((_, path)) = latest {
+            self.load_state(path.to_str().unwrap());
+        }
+    }
+
     pub fn run(&mut self) {
-        let raw_data = (self.mem[self.pc], self.mem[self.pc +1]); 
-        self.inst.decode(raw_data); 
+        if self.jit.is_some() {
+            self.run_jit_block();
+            return;
+        }
+
+        self.run_interpreted();
+    }
+
+    fn run_interpreted(&mut self) {
+        let raw_data = (self.mem[self.pc], self.mem[self.pc.wrapping_add(1)]);
+        self.inst.decode(raw_data);
 
         self.jmp  = false;
 
-        // println!("{:#x}: {:#x}", self.pc, self.inst.opcode);
-
-        match Opcodes::from_u16(self.inst.opcode).unwrap() {
-            Opcodes::CLS    => self.cls(),
-            Opcodes::RET    => self.ret(),
-            Opcodes::JMP    => self.jmp(),
-            Opcodes::JMP_VA => self.jmp_va(),
-            Opcodes::CALL   => self.call(),
-            Opcodes::SE_VB  => self.se_vb(),
-            Opcodes::SE_VV  => self.se_vv(),
-            Opcodes::SNE_VB => self.sne_vb(),
-            Opcodes::SNE_VV => self.sne_vv(),
-            Opcodes::OR     => self.or(),
-            Opcodes::ADD_VB => self.add_vb(),
-            Opcodes::ADD_VV => self.add_vv(),
-            Opcodes::ADD_IV => self.add_iv(),
-            Opcodes::SUB    => self.sub(),
-            Opcodes::SUBN   => self.subn(),
-            Opcodes::XOR    => self.xor(),
-            Opcodes::AND    => self.and(),
-            Opcodes::LD_VB  => self.ld_vb(),
-            Opcodes::LD_BV  => self.ld_bv(),
-            Opcodes::LD_VV  => self.ld_vv(),
-            Opcodes::LD_VI  => self.ld_vi(),
-            Opcodes::LD_VK  => self.ld_vk(),
-            Opcodes::LD_IV  => self.ld_iv(),
-            Opcodes::LD_FV  => self.ld_fv(),
-            Opcodes::LD_IA  => self.ld_ia(),
-            Opcodes::LD_VDT => self.ld_vdt(),
-            Opcodes::LD_DTV => self.ld_dtv(),
-            Opcodes::LD_STV => self.ld_stv(),
-            Opcodes::SHL    => self.shl(),
-            Opcodes::SKP    => self.skp(),
-            Opcodes::SKNP   => self.sknp(),
-            Opcodes::RND    => self.rnd(),
-            Opcodes::SHR    => self.shr(),
-            Opcodes::DRW    => self.drw(),
-            _               => panic!("Unrecognized opcode: {:#x}", self.inst.opcode),
+        // XO-CHIP long addressing: `F000 nnnn` loads a 16-bit immediate
+        // (the word following this instruction) into `i`, rather than the
+        // usual 12-bit `nnn` a normal opcode carries.
+        if self.inst.opcode == 0xF000 {
+            if self.debug & DBG_CPU != 0 {
+                println!(
+                    "{:#06x}: {:#06x} LD_IA_LONG {:?} i={:#06x}",
+                    self.pc, self.inst.opcode, &self.regs[..], self.i
+                );
+            }
+
+            self.ld_ia_long();
+        } else {
+            let opcode = Opcodes::from_u16(self.inst.opcode).unwrap();
+
+            if self.debug & DBG_CPU != 0 {
+                println!(
+                    "{:#06x}: {:#06x} {:<8?} {:?} i={:#06x}",
+                    self.pc, self.inst.opcode, opcode, &self.regs[..], self.i
+                );
+            }
+
+            match opcode {
+                Opcodes::CLS    => self.cls(),
+                Opcodes::RET    => self.ret(),
+                Opcodes::JMP    => self.jmp(),
+                Opcodes::JMP_VA => self.jmp_va(),
+                Opcodes::CALL   => self.call(),
+                Opcodes::SE_VB  => self.se_vb(),
+                Opcodes::SE_VV  => self.se_vv(),
+                Opcodes::SNE_VB => self.sne_vb(),
+                Opcodes::SNE_VV => self.sne_vv(),
+                Opcodes::OR     => self.or(),
+                Opcodes::ADD_VB => self.add_vb(),
+                Opcodes::ADD_VV => self.add_vv(),
+                Opcodes::ADD_IV => self.add_iv(),
+                Opcodes::SUB    => self.sub(),
+                Opcodes::SUBN   => self.subn(),
+                Opcodes::XOR    => self.xor(),
+                Opcodes::AND    => self.and(),
+                Opcodes::LD_VB  => self.ld_vb(),
+                Opcodes::LD_BV  => self.ld_bv(),
+                Opcodes::LD_VV  => self.ld_vv(),
+                Opcodes::LD_VI  => self.ld_vi(),
+                Opcodes::LD_VK  => self.ld_vk(),
+                Opcodes::LD_IV  => self.ld_iv(),
+                Opcodes::LD_FV  => self.ld_fv(),
+                Opcodes::LD_IA  => self.ld_ia(),
+                Opcodes::LD_VDT => self.ld_vdt(),
+                Opcodes::LD_DTV => self.ld_dtv(),
+                Opcodes::LD_STV => self.ld_stv(),
+                Opcodes::SHL    => self.shl(),
+                Opcodes::SKP    => self.skp(),
+                Opcodes::SKNP   => self.sknp(),
+                Opcodes::RND    => self.rnd(),
+                Opcodes::SHR    => self.shr(),
+                Opcodes::DRW    => self.drw(),
+                _               => panic!("Unrecognized opcode: {:#x}", self.inst.opcode),
+            }
         }
 
         if !self.jmp { self.inc_pc(); }
 
         if self.dt > 0 { self.dt -= 1; }
         if self.st > 0 { self.st -= 1; }
+
+        self.audio.update(self.st);
     }
 
     fn set_pc(&mut self, addr: u16) {
@@ -164,7 +463,8 @@ impl Chip8 {
     }
 
     fn jmp_va(&mut self) {
-        let offset = self.regs[0] as u16;
+        let offset_reg = if self.quirks.jump_offset_uses_vx { self.inst.x } else { 0 };
+        let offset = self.regs[offset_reg] as u16;
         let addr = self.inst.nnn + offset;
         self.set_pc(addr);
         self.jmp = true;
@@ -203,10 +503,12 @@ impl Chip8 {
 
     fn or(&mut self) {
         self.regs[self.inst.x] |= self.regs[self.inst.y];
+        if self.quirks.vf_reset_on_logic { self.regs[0xF] = 0; }
     }
 
     fn and(&mut self) {
         self.regs[self.inst.x] &= self.regs[self.inst.y];
+        if self.quirks.vf_reset_on_logic { self.regs[0xF] = 0; }
     }
 
     fn add_vb(&mut self) {
@@ -225,9 +527,10 @@ impl Chip8 {
     }
 
     fn add_iv(&mut self) {
-        let x   = self.regs[self.inst.x] as u16;
-        self.regs[0xF] = ((self.i + x) > 255) as u8;
-        self.i += x;
+        let x = self.regs[self.inst.x] as u16;
+        let (sum, overflowed) = self.i.overflowing_add(x);
+        self.regs[0xF] = overflowed as u8;
+        self.i = sum;
     }
 
     fn sub(&mut self) {
@@ -235,7 +538,7 @@ impl Chip8 {
         let x = self.regs[idx_x];
         let y = self.regs[self.inst.y];
 
-        self.regs[0xF] = (x > y) as u8;
+        self.regs[0xF] = (x >= y) as u8;
         self.regs[idx_x] = x.wrapping_sub(y);
     }
 
@@ -243,27 +546,37 @@ impl Chip8 {
         let x = self.regs[self.inst.x];
         let y = self.regs[self.inst.y];
 
-        self.regs[0xF] = (y > x) as u8;
+        self.regs[0xF] = (y >= x) as u8;
         self.regs[self.inst.x] = y.wrapping_sub(x);
     }
 
     fn xor(&mut self) {
         self.regs[self.inst.x] ^= self.regs[self.inst.y];
+        if self.quirks.vf_reset_on_logic { self.regs[0xF] = 0; }
     }
 
     fn shr(&mut self) {
         let idx_x = self.inst.x;
-        let x     = self.regs[idx_x];
-        self.regs[0xF] = x & 0x1;
-        self.regs[idx_x] = x >> 1;
+        let src = if self.quirks.shift_uses_vy {
+            self.regs[self.inst.y]
+        } else {
+            self.regs[idx_x]
+        };
+
+        self.regs[0xF] = src & 0x1;
+        self.regs[idx_x] = src >> 1;
     }
 
     fn shl(&mut self) {
-        let x = self.regs[self.inst.x];
-        let y = self.regs[self.inst.y];
+        let idx_x = self.inst.x;
+        let src = if self.quirks.shift_uses_vy {
+            self.regs[self.inst.y]
+        } else {
+            self.regs[idx_x]
+        };
 
-        self.regs[0xF] = ((x & 0xF0) >> 7 == 1) as u8;
-        self.regs[self.inst.x] *= 2;
+        self.regs[0xF] = (src & 0x80 != 0) as u8;
+        self.regs[idx_x] = src << 1;
     }
 
     fn ld_vv(&mut self) {
@@ -277,9 +590,9 @@ impl Chip8 {
     fn ld_bv(&mut self) {
         let x = self.regs[self.inst.x];
 
-        self.mem[self.i as usize]     = x / 100;
-        self.mem[self.i as usize + 1] = (x / 100) % 10;
-        self.mem[self.i as usize + 2] = (x % 100) % 10; 
+        self.write_mem(self.i as usize, x / 100);
+        self.write_mem(self.i.wrapping_add(1) as usize, (x / 100) % 10);
+        self.write_mem(self.i.wrapping_add(2) as usize, (x % 100) % 10);
     }
 
     fn ld_fv(&mut self) {
@@ -289,17 +602,34 @@ impl Chip8 {
 
     fn ld_vi(&mut self) {
         let x = self.inst.x as usize;
+        let count = if self.quirks.inclusive_register_range { x + 1 } else { x };
+
+        for i in 0usize..count {
+            self.regs[i] = self.read_mem(self.i.wrapping_add(i as u16) as usize);
+        }
 
-        for i in 0usize..x {
-            self.regs[i] = self.mem[self.i as usize + i];  
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
     }
 
     fn ld_iv(&mut self) {
         let x = self.inst.x as usize;
+        let count = if self.quirks.inclusive_register_range { x + 1 } else { x };
 
-        for i in 0usize..x {
-            self.mem[self.i as usize + i] = self.regs[i]; 
+        for i in 0usize..count {
+            self.write_mem(self.i.wrapping_add(i as u16) as usize, self.regs[i]);
+        }
+
+        // This can overwrite bytes the JIT already compiled a block from;
+        // drop any cached block covering the written range so it gets
+        // recompiled from the now-current memory.
+        if let Some(ref mut jit) = self.jit {
+            jit.invalidate_range(self.i as usize, count);
+        }
+
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
     }
 
@@ -307,6 +637,15 @@ impl Chip8 {
         self.i = self.inst.nnn;
     }
 
+    // XO-CHIP `F000 nnnn`: the 16-bit operand lives in the word right after
+    // this instruction, so `i` isn't limited to the usual 12-bit `nnn`.
+    fn ld_ia_long(&mut self) {
+        let hi = self.read_mem(self.pc.wrapping_add(2)) as u16;
+        let lo = self.read_mem(self.pc.wrapping_add(3)) as u16;
+        self.i = (hi << 8) | lo;
+        self.inc_pc();
+    }
+
     fn ld_vk(&mut self) {
         let mut key_pressed = false;
 
@@ -348,7 +687,7 @@ impl Chip8 {
         self.regs[0xF] = 0;
 
         for i in 0..n {
-            let px = self.mem[(self.i + i as u16) as usize];
+            let px = self.read_mem(self.i.wrapping_add(i as u16) as usize);
             for j in 0..8 {
                 if px & (0x80 >> j) != 0 {
                     let mut offset = (x+j+(y+i)*64) as u16;
@@ -0,0 +1,396 @@
+// Optional JIT backend: translates CHIP-8 basic blocks to native x86_64 and
+// caches them by entry PC, so hot loops stop paying the decode-and-match
+// cost of the interpreter on every pass. Anything the block compiler
+// doesn't know how to translate natively falls back to the interpreter.
+
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+
+use libc::{c_void, mmap, munmap, MAP_ANON, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use ep::FromPrimitive;
+use rand::{thread_rng, Rng};
+
+use instruction::{Instruction, Opcodes};
+
+// A compiled block's native entry point. `regs` points at `Chip8::regs`
+// (16 contiguous bytes) and `i` at `Chip8::i`; the generated code reads and
+// writes through these pointers directly instead of marshalling arguments.
+// Returns the CHIP-8 address execution left off at, so the interpreter
+// (or the JIT dispatch loop) knows where to resume.
+pub type BlockFn = extern "C" fn(regs: *mut u8, i: *mut u16) -> u16;
+
+struct ExecBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecBuffer {
+    fn new(code: &[u8]) -> ExecBuffer {
+        unsafe {
+            let ptr = mmap(
+                ptr::null_mut(),
+                code.len(),
+                PROT_READ | PROT_WRITE | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANON,
+                -1,
+                0,
+            ) as *mut u8;
+
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+
+            ExecBuffer { ptr, len: code.len() }
+        }
+    }
+
+    fn entry(&self) -> BlockFn {
+        unsafe { mem::transmute(self.ptr) }
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr as *mut c_void, self.len); }
+    }
+}
+
+pub struct CompiledBlock {
+    buffer:          ExecBuffer,
+    pub start_addr:  usize,
+    pub end_addr:    usize,
+    // Offsets (into `buffer`) of `jmp rel32` instructions left pointing at the
+    // interpreter trampoline, keyed by the CHIP-8 address they should
+    // eventually jump to once that block is also resident.
+    unlinked_jumps:  Vec<(usize, usize)>,
+    // Entry PCs of other blocks this one has already been linked directly
+    // to, natively. Used to detect when linking one more edge would close a
+    // cycle of native jumps that never returns to `run_jit_block`.
+    linked_to:       Vec<usize>,
+}
+
+pub struct Jit {
+    blocks: HashMap<usize, CompiledBlock>,
+}
+
+// Whether `opcode` can start a native block at all. Anything else (`CALL`,
+// `RET`, the `SE`/`SNE`/`SKP`/`SKNP` family, `DRW`, ...) needs interpreter
+// state (the stack, `keys`, `screen`) and should just run interpreted.
+pub fn starts_block(opcode: Opcodes) -> bool {
+    match opcode {
+        Opcodes::OR | Opcodes::AND | Opcodes::XOR
+            | Opcodes::ADD_VB | Opcodes::ADD_VV
+            | Opcodes::SUB | Opcodes::SUBN
+            | Opcodes::SHR | Opcodes::SHL
+            | Opcodes::LD_VB | Opcodes::LD_VV
+            | Opcodes::RND | Opcodes::JMP => true,
+        _ => false,
+    }
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit { blocks: HashMap::new() }
+    }
+
+    pub fn lookup(&self, pc: usize) -> Option<&CompiledBlock> {
+        self.blocks.get(&pc)
+    }
+
+    pub fn call(&self, block: &CompiledBlock, regs: *mut u8, i: *mut u16) -> u16 {
+        (block.buffer.entry())(regs, i)
+    }
+
+    // Self-modifying code (`ld_iv`) can overwrite bytes a block was already
+    // compiled from; drop any cached block whose source range overlaps the
+    // write so the next dispatch recompiles from the updated memory.
+    //
+    // A block that was `link()`-patched to jump straight into one being
+    // dropped here has a native `jmp` baked into it pointing at memory
+    // that's about to be unmapped, so the invalidation has to cascade to
+    // it too -- otherwise the next time it runs, it jumps into freed
+    // memory instead of returning to the interpreter.
+    pub fn invalidate_range(&mut self, addr: usize, len: usize) {
+        let end = addr + len;
+        let mut removed: Vec<usize> = self.blocks.iter()
+            .filter(|&(_, b)| !(b.end_addr <= addr || b.start_addr >= end))
+            .map(|(&pc, _)| pc)
+            .collect();
+
+        loop {
+            let newly_removed: Vec<usize> = self.blocks.keys()
+                .filter(|pc| !removed.contains(pc))
+                .filter(|pc| {
+                    self.blocks[pc].linked_to.iter().any(|to| removed.contains(to))
+                })
+                .cloned()
+                .collect();
+
+            if newly_removed.is_empty() {
+                break;
+            }
+            removed.extend(newly_removed);
+        }
+
+        for pc in &removed {
+            self.blocks.remove(pc);
+        }
+    }
+
+    // Compiles the basic block starting at `entry_pc`: straight-line ALU and
+    // load ops are emitted as native instructions over `regs`/`i`; the block
+    // ends at an unconditional `JMP` or at the first op it doesn't translate
+    // (other control flow, `DRW`, key/timer/`I` ops, ...), whose address is
+    // returned so the interpreter runs it.
+    pub fn compile(&mut self, mem: &[u8], entry_pc: usize, vf_reset_on_logic: bool, shift_uses_vy: bool) {
+        let mut code: Vec<u8> = Vec::new();
+        let mut inst = Instruction::new();
+        let mut pc = entry_pc;
+        let mut jmp_target: Option<usize> = None;
+
+        loop {
+            // `pc` can run past the top of the 64 KB address space when a
+            // block's last instruction sits right at the end of memory;
+            // wrap the fetch the same way CHIP-8 addressing wraps elsewhere.
+            let fetch_pc = pc % mem.len();
+            let raw = (mem[fetch_pc], mem[(fetch_pc + 1) % mem.len()]);
+            inst.decode(raw);
+
+            let opcode = match Opcodes::from_u16(inst.opcode) {
+                Some(op) => op,
+                None     => break,
+            };
+
+            match opcode {
+                Opcodes::OR     => emit_alu_rr(&mut code, 0x0A, inst.x, inst.y, vf_reset_on_logic), // or  al, [rdi+y]
+                Opcodes::AND    => emit_alu_rr(&mut code, 0x22, inst.x, inst.y, vf_reset_on_logic), // and al, [rdi+y]
+                Opcodes::XOR    => emit_alu_rr(&mut code, 0x32, inst.x, inst.y, vf_reset_on_logic), // xor al, [rdi+y]
+                Opcodes::ADD_VB => emit_add_imm(&mut code, inst.x, inst.kk),
+                Opcodes::ADD_VV => emit_add_rr(&mut code, inst.x, inst.y),
+                Opcodes::SUB    => emit_sub_rr(&mut code, inst.x, inst.y, false),
+                Opcodes::SUBN   => emit_sub_rr(&mut code, inst.x, inst.y, true),
+                Opcodes::SHR    => emit_shift(&mut code, inst.x, inst.y, false, shift_uses_vy),
+                Opcodes::SHL    => emit_shift(&mut code, inst.x, inst.y, true, shift_uses_vy),
+                Opcodes::LD_VB  => emit_ld_imm(&mut code, inst.x, inst.kk),
+                Opcodes::LD_VV  => emit_ld_reg(&mut code, inst.x, inst.y),
+                Opcodes::RND    => emit_rnd(&mut code, inst.x, inst.kk),
+                Opcodes::JMP    => { jmp_target = Some(inst.nnn as usize); pc += 2; break; }
+                _               => break,
+            }
+
+            pc += 2;
+        }
+
+        let mut unlinked_jumps = Vec::new();
+
+        match jmp_target {
+            Some(target) => {
+                // Unconditional jump: leave a `jmp rel32` aimed at a small
+                // fallback stub (emitted right after it) that hands the
+                // target address back to the interpreter. `link()` can
+                // later repoint this same displacement at the target
+                // block's native entry once it's compiled, skipping the
+                // trampoline entirely.
+                let jmp_offset = code.len();
+                emit_placeholder_jmp(&mut code);
+
+                let stub_offset = code.len();
+                emit_return(&mut code, target as u16);
+
+                let disp = (stub_offset as isize) - (jmp_offset as isize + 5);
+                code[jmp_offset + 1..jmp_offset + 5]
+                    .copy_from_slice(&(disp as i32).to_le_bytes());
+
+                unlinked_jumps.push((jmp_offset, target));
+            }
+            None => {
+                // The terminating op (any opcode not translated above) is
+                // left entirely to the interpreter.
+                emit_return(&mut code, pc as u16);
+            }
+        }
+
+        let buffer = ExecBuffer::new(&code);
+
+        self.blocks.insert(entry_pc, CompiledBlock {
+            buffer,
+            start_addr: entry_pc,
+            end_addr:   pc,
+            unlinked_jumps,
+            linked_to:  Vec::new(),
+        });
+    }
+
+    // Whether linking `from -> to` would close a cycle of native jumps,
+    // i.e. `to` can already reach `from` through edges linked so far. CHIP-8
+    // loops (a single self-looping block, or several blocks chained in a
+    // ring) compile down to exactly this shape, and patching the closing
+    // edge would turn the whole cycle into a native `jmp` that never
+    // returns to `run_jit_block` again -- freezing timers, audio and input
+    // polling along with it.
+    fn would_cycle(&self, from: usize, to: usize) -> bool {
+        let mut stack = vec![to];
+        let mut seen = Vec::new();
+
+        while let Some(pc) = stack.pop() {
+            if pc == from {
+                return true;
+            }
+            if seen.contains(&pc) {
+                continue;
+            }
+            seen.push(pc);
+
+            if let Some(block) = self.blocks.get(&pc) {
+                stack.extend(block.linked_to.iter().cloned());
+            }
+        }
+
+        false
+    }
+
+    // Patches a previously-emitted trampoline jump in `from` to instead jump
+    // directly into `to`'s native entry point, when both blocks are
+    // resident, the displacement fits in a rel32, and doing so wouldn't
+    // close a cycle (see `would_cycle`). Falls back to leaving the
+    // trampoline in place otherwise, so that edge still returns to Rust
+    // once per block.
+    pub fn link(&mut self, from_pc: usize, to_pc: usize) {
+        if from_pc == to_pc || self.would_cycle(from_pc, to_pc) {
+            return;
+        }
+
+        let to_entry = match self.blocks.get(&to_pc) {
+            Some(block) => block.buffer.ptr as isize,
+            None        => return,
+        };
+
+        if let Some(from) = self.blocks.get_mut(&from_pc) {
+            if let Some(pos) = from.unlinked_jumps.iter().position(|&(_, pc)| pc == to_pc) {
+                let (offset, _) = from.unlinked_jumps.remove(pos);
+                let from_addr = unsafe { from.buffer.ptr.add(offset) } as isize;
+
+                // `jmp rel32` is 5 bytes (opcode + 4-byte displacement); the
+                // displacement is relative to the address *after* the jump.
+                let disp = to_entry - (from_addr + 5);
+
+                if disp >= i32::min_value() as isize && disp <= i32::max_value() as isize {
+                    let disp32 = disp as i32;
+                    unsafe {
+                        let dst = from.buffer.ptr.add(offset);
+                        ptr::copy_nonoverlapping(disp32.to_le_bytes().as_ptr(), dst.add(1), 4);
+                    }
+                    from.linked_to.push(to_pc);
+                }
+                // else: displacement doesn't fit in rel32, leave the
+                // trampoline return in place and keep using the interpreter
+                // for that edge.
+            }
+        }
+    }
+}
+
+fn reg_ptr(code: &mut Vec<u8>, opcode: u8, dst_reg_bits: u8, offset: u8) {
+    // ModRM for `[rdi+disp8]`: mod=01, reg=dst_reg_bits, rm=111 (rdi).
+    code.push(opcode);
+    code.push(0b01_000_111 | (dst_reg_bits << 3));
+    code.push(offset);
+}
+
+fn emit_ld_imm(code: &mut Vec<u8>, x: usize, kk: u8) {
+    code.push(0xB0); // mov al, imm8
+    code.push(kk);
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+}
+
+fn emit_ld_reg(code: &mut Vec<u8>, x: usize, y: usize) {
+    reg_ptr(code, 0x8A, 0, y as u8); // mov al, [rdi+y]
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+}
+
+fn emit_alu_rr(code: &mut Vec<u8>, opcode: u8, x: usize, y: usize, reset_vf: bool) {
+    reg_ptr(code, 0x8A, 0, x as u8); // mov al, [rdi+x]
+    reg_ptr(code, opcode, 0, y as u8); // <op> al, [rdi+y]
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+
+    // Original CHIP-8 resets VF after OR/AND/XOR; CHIP-48/SCHIP leave it.
+    if reset_vf {
+        code.push(0xC6); code.push(0x47); code.push(0x0F); code.push(0x00); // mov byte [rdi+0xF], 0
+    }
+}
+
+fn emit_add_imm(code: &mut Vec<u8>, x: usize, kk: u8) {
+    reg_ptr(code, 0x8A, 0, x as u8); // mov al, [rdi+x]
+    code.push(0x04); // add al, imm8
+    code.push(kk);
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+}
+
+fn emit_add_rr(code: &mut Vec<u8>, x: usize, y: usize) {
+    reg_ptr(code, 0x8A, 0, x as u8); // mov al, [rdi+x]
+    reg_ptr(code, 0x02, 0, y as u8); // add al, [rdi+y]  (sets CF on overflow)
+    code.push(0x0F); code.push(0x92); code.push(0xC1); // setb cl
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+    reg_ptr(code, 0x88, 1, 0x0F);    // mov [rdi+0xF], cl
+}
+
+fn emit_sub_rr(code: &mut Vec<u8>, x: usize, y: usize, negated: bool) {
+    let (minuend, subtrahend, dest) = if negated { (y, x, x) } else { (x, y, x) };
+
+    reg_ptr(code, 0x8A, 0, minuend as u8);    // mov al, [rdi+minuend]
+    reg_ptr(code, 0x2A, 0, subtrahend as u8); // sub al, [rdi+subtrahend]
+    code.push(0x0F); code.push(0x93); code.push(0xC1); // setae cl (no borrow -> VF=1)
+    reg_ptr(code, 0x88, 0, dest as u8); // mov [rdi+dest], al
+    reg_ptr(code, 0x88, 1, 0x0F);       // mov [rdi+0xF], cl
+}
+
+fn emit_shift(code: &mut Vec<u8>, x: usize, y: usize, left: bool, shift_uses_vy: bool) {
+    // CHIP-8 (COSMAC VIP) shifts Vy into Vx; CHIP-48/SCHIP shift Vx in
+    // place. The result always lands in Vx regardless of the source.
+    let src = if shift_uses_vy { y } else { x };
+    reg_ptr(code, 0x8A, 0, src as u8); // mov al, [rdi+src]
+
+    if left {
+        code.push(0xC0); code.push(0xE0); code.push(0x01); // shl al, 1 (bit 7 -> CF)
+        code.push(0x0F); code.push(0x92); code.push(0xC1); // setb cl
+    } else {
+        code.push(0xC0); code.push(0xE8); code.push(0x01); // shr al, 1 (bit 0 -> CF)
+        code.push(0x0F); code.push(0x92); code.push(0xC1); // setb cl
+    }
+
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+    reg_ptr(code, 0x88, 1, 0x0F);    // mov [rdi+0xF], cl
+}
+
+// Called from native code to draw a random byte. rdtsc's low bits are
+// strongly correlated across back-to-back calls in a tight loop, which
+// would make JIT-mode randomness worse -- and different from -- the
+// interpreter's `thread_rng()`, so `emit_rnd` calls back into this instead.
+extern "C" fn jit_rand_u8() -> u8 {
+    thread_rng().gen::<u8>()
+}
+
+fn emit_rnd(code: &mut Vec<u8>, x: usize, kk: u8) {
+    let addr = jit_rand_u8 as usize as u64;
+
+    code.push(0x57); // push rdi (preserve the regs pointer across the call)
+
+    code.push(0x48); code.push(0xB8); // movabs rax, imm64
+    code.extend_from_slice(&addr.to_le_bytes());
+    code.push(0xFF); code.push(0xD0); // call rax -> al = random byte
+
+    code.push(0x5F); // pop rdi
+
+    code.push(0x24); code.push(kk);  // and al, kk
+    reg_ptr(code, 0x88, 0, x as u8); // mov [rdi+x], al
+}
+
+fn emit_placeholder_jmp(code: &mut Vec<u8>) {
+    code.push(0xE9); // jmp rel32
+    code.extend_from_slice(&[0, 0, 0, 0]);
+}
+
+fn emit_return(code: &mut Vec<u8>, resume_pc: u16) {
+    code.push(0x66); code.push(0xB8); // mov ax, imm16
+    code.extend_from_slice(&resume_pc.to_le_bytes());
+    code.push(0xC3); // ret
+}